@@ -0,0 +1,346 @@
+//! Dynamic diagnostic session helper
+//! 
+
+use std::{borrow::BorrowMut, sync::{Arc, Mutex}};
+
+use crate::{DiagError, DiagServerResult, channel::{IsoTPSettings}, dtc::DTC, hardware::Hardware, kwp2000::{self, Kwp2000DiagnosticServer, Kwp2000ServerOptions, Kwp2000VoidHandler}, uds::{self, UdsDiagnosticServer, UdsServerOptions, UdsVoidHandler}};
+
+mod retry;
+mod scheduler;
+pub use retry::{with_pending_retry, PendingRetryOptions};
+pub use scheduler::{PollHandle, PollRequest, PollScheduler};
+
+/// Dynamic diagnostic session
+///
+/// This is used if a target ECU has an unknown diagnostic protocol.
+///
+/// This also contains some useful wrappers for basic functions such as
+/// reading and clearing error codes.
+#[derive(Debug)]
+pub struct DynamicDiagSession {
+    session: DynamicSessionType,
+    scheduler: PollScheduler,
+    retry_opts: PendingRetryOptions,
+}
+
+#[derive(Debug)]
+enum DynamicSessionType {
+    Kwp(Kwp2000DiagnosticServer),
+    Uds(UdsDiagnosticServer)
+}
+
+impl DynamicDiagSession {
+    /// Creates a new dynamic session.
+    /// This will first try with KWP2000, then if that fails,
+    /// will try with UDS. If both server creations fail,
+    /// then the last error will be returned.
+    /// 
+    /// NOTE: In order to test if the ECU supports the protocol,
+    /// the ECU will be put into extended diagnostic session briefly to test
+    /// if it supports the tested diagnostic protocol.
+    #[allow(unused_must_use, unused_assignments)]
+    pub fn new_over_iso_tp<C>(
+        hw_device: Arc<Mutex<C>>,
+        channel_cfg: IsoTPSettings,
+        tx_id: u32,
+        rx_id: u32,
+    ) -> DiagServerResult<Self>
+    where
+        C: Hardware + 'static 
+    {
+
+        let mut last_err : Option<DiagError>; // Setting up last recorded error
+
+        // Create iso tp channel using provided HW interface. If this fails, we cannot setup KWP or UDS session!
+        let mut iso_tp_channel = Hardware::create_iso_tp_channel(hw_device.clone())?;
+
+        // Firstly, try KWP2000
+        match Kwp2000DiagnosticServer::new_over_iso_tp(Kwp2000ServerOptions { 
+            send_id: tx_id, 
+            recv_id: rx_id, 
+            read_timeout_ms: 1500, 
+            write_timeout_ms: 1500, 
+            global_tp_id: 0x00, 
+            tester_present_interval_ms: 2000, 
+            tester_present_require_response: true 
+        }, iso_tp_channel, channel_cfg, Kwp2000VoidHandler{}) {
+            Ok(mut kwp) => {
+                if kwp2000::set_diagnostic_session_mode(&mut kwp, kwp2000::SessionType::ExtendedDiagnostics).is_ok() {
+                    // KWP accepted! The ECU supports KWP2000!
+                    // Return the ECU back to normal mode
+                    kwp2000::set_diagnostic_session_mode(&mut kwp, kwp2000::SessionType::Normal);
+                    return Ok(Self {
+                        session: DynamicSessionType::Kwp(kwp),
+                        scheduler: PollScheduler::default(),
+                        retry_opts: PendingRetryOptions::default(),
+                    })
+                } else {
+                    last_err = Some(DiagError::NotSupported)
+                }
+            },
+            Err(e) => { last_err = Some(e); }
+        }
+
+        iso_tp_channel = Hardware::create_iso_tp_channel(hw_device)?;
+        match UdsDiagnosticServer::new_over_iso_tp(UdsServerOptions { 
+            send_id: tx_id, 
+            recv_id: rx_id, 
+            read_timeout_ms: 1500, 
+            write_timeout_ms: 1500, 
+            global_tp_id: 0x00, 
+            tester_present_interval_ms: 2000, 
+            tester_present_require_response: true 
+        }, iso_tp_channel, channel_cfg, UdsVoidHandler{}) {
+            Ok(mut uds) => {
+                if uds::set_extended_mode(&mut uds).is_ok() {
+                    // UDS accepted! The ECU supports UDS!
+                    // Return the ECU back to normal mode
+                    uds::set_default_mode(&mut uds);
+                    return Ok(Self {
+                        session: DynamicSessionType::Uds(uds),
+                        scheduler: PollScheduler::default(),
+                        retry_opts: PendingRetryOptions::default(),
+                    })
+                } else {
+                    last_err = Some(DiagError::NotSupported)
+                }
+            },
+            Err(e) => { last_err = Some(e); }
+        }
+        Err(last_err.unwrap())
+    }
+
+    /// Returns a reference to KWP2000 session. None is returned if server type is not KWP2000
+    pub fn as_kwp_session(&'_ mut self) -> Option<&'_ mut Kwp2000DiagnosticServer> {
+        if let DynamicSessionType::Kwp(kwp) = self.session.borrow_mut() {
+            Some(kwp)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a reference to UDS session. None is returned if server type is not UDS
+    pub fn as_uds_session(&'_ mut self) -> Option<&'_ mut UdsDiagnosticServer> {
+        if let DynamicSessionType::Uds(uds) = self.session.borrow_mut() {
+            Some(uds)
+        } else {
+            None
+        }
+    }
+
+    /// Puts the ECU into an extended diagnostic session
+    pub fn enter_extended_diagnostic_mode(&mut self) -> DiagServerResult<()> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(k) => {
+                let original_timeout = kwp2000::get_read_timeout(k);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = kwp2000::set_read_timeout(k, opts.per_attempt_timeout_ms);
+                    }
+                    kwp2000::set_diagnostic_session_mode(k, kwp2000::SessionType::ExtendedDiagnostics)
+                });
+                let _ = kwp2000::set_read_timeout(k, original_timeout);
+                result
+            }
+            DynamicSessionType::Uds(u) => {
+                let original_timeout = uds::get_read_timeout(u);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = uds::set_read_timeout(u, opts.per_attempt_timeout_ms);
+                    }
+                    uds::set_extended_mode(u)
+                });
+                let _ = uds::set_read_timeout(u, original_timeout);
+                result
+            }
+        }
+    }
+
+    /// Puts the ECU into a default diagnostic session. This is how the ECU normally operates
+    pub fn enter_default_diagnostic_mode(&mut self) -> DiagServerResult<()> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(k) => {
+                let original_timeout = kwp2000::get_read_timeout(k);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = kwp2000::set_read_timeout(k, opts.per_attempt_timeout_ms);
+                    }
+                    kwp2000::set_diagnostic_session_mode(k, kwp2000::SessionType::Normal)
+                });
+                let _ = kwp2000::set_read_timeout(k, original_timeout);
+                result
+            }
+            DynamicSessionType::Uds(u) => {
+                let original_timeout = uds::get_read_timeout(u);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = uds::set_read_timeout(u, opts.per_attempt_timeout_ms);
+                    }
+                    uds::set_default_mode(u)
+                });
+                let _ = uds::set_read_timeout(u, original_timeout);
+                result
+            }
+        }
+    }
+
+    /// Reads all diagnostic trouble codes from the ECU
+    pub fn read_all_dtcs(&mut self) -> DiagServerResult<Vec<DTC>> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(k) => {
+                let original_timeout = kwp2000::get_read_timeout(k);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = kwp2000::set_read_timeout(k, opts.per_attempt_timeout_ms);
+                    }
+                    kwp2000::read_stored_dtcs(k, kwp2000::DTCRange::All)
+                });
+                let _ = kwp2000::set_read_timeout(k, original_timeout);
+                result
+            }
+            DynamicSessionType::Uds(u) => {
+                let original_timeout = uds::get_read_timeout(u);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = uds::set_read_timeout(u, opts.per_attempt_timeout_ms);
+                    }
+                    uds::get_dtcs_by_status_mask(u, 0xFF)
+                });
+                let _ = uds::set_read_timeout(u, original_timeout);
+                result
+            }
+        }
+    }
+
+    /// Attempts to clear all DTCs stored on the ECU
+    pub fn clear_all_dtcs(&mut self) -> DiagServerResult<()> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(k) => {
+                let original_timeout = kwp2000::get_read_timeout(k);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = kwp2000::set_read_timeout(k, opts.per_attempt_timeout_ms);
+                    }
+                    kwp2000::clear_dtc(k, kwp2000::ClearDTCRange::AllDTCs)
+                });
+                let _ = kwp2000::set_read_timeout(k, original_timeout);
+                result
+            }
+            DynamicSessionType::Uds(u) => {
+                let original_timeout = uds::get_read_timeout(u);
+                let result = with_pending_retry(opts, |is_retry| {
+                    if is_retry {
+                        let _ = uds::set_read_timeout(u, opts.per_attempt_timeout_ms);
+                    }
+                    uds::clear_diagnostic_information(u, 0x00FFFFFF)
+                });
+                let _ = uds::set_read_timeout(u, original_timeout);
+                result
+            }
+        }
+    }
+
+    /// Returns the current ResponsePending / BusyRepeatRequest retry budgets.
+    pub fn pending_retry_options(&self) -> PendingRetryOptions {
+        self.retry_opts
+    }
+
+    /// Configures how many times a request is re-sent after NRC 0x78
+    /// (ResponsePending) or NRC 0x21 (BusyRepeatRequest) before giving up,
+    /// and the read timeout applied on each retry. Useful when
+    /// flashing an ECU, where routine control and block transfers can take
+    /// far longer than a normal request.
+    pub fn set_pending_retry_options(&mut self, opts: PendingRetryOptions) {
+        self.retry_opts = opts;
+    }
+
+    /// Sets the maximum number of polling requests the background scheduler
+    /// will allow in flight at once. Must be called before
+    /// [`Self::start_poll_worker`].
+    pub fn set_max_in_flight_polls(&mut self, max_in_flight: usize) {
+        self.scheduler.set_max_in_flight(max_in_flight);
+    }
+
+    /// Registers a recurring or one-shot diagnostic request with the
+    /// background scheduler. Does nothing until [`Self::start_poll_worker`]
+    /// has been called on a shared handle to this session.
+    ///
+    /// Returns [`None`] if `request` is a one-shot request whose dedup key
+    /// matches a request that is already pending.
+    pub fn register_poll(&self, request: PollRequest) -> Option<PollHandle> {
+        self.scheduler.register(request)
+    }
+
+    /// Cancels a previously registered poll. Returns `false` if `handle` is
+    /// unknown (for example, a one-shot request that already completed).
+    pub fn cancel_poll(&self, handle: PollHandle) -> bool {
+        self.scheduler.cancel(handle)
+    }
+
+    /// Starts the background worker thread which dispatches due polls
+    /// registered via [`Self::register_poll`]. `this` must be a shared
+    /// handle to the session the worker will issue requests against.
+    pub fn start_poll_worker(this: &Arc<Mutex<Self>>) {
+        this.lock().unwrap().scheduler.spawn_worker(this.clone());
+    }
+
+    /// Stops the background poll worker started by [`Self::start_poll_worker`].
+    ///
+    /// Takes the same shared handle `start_poll_worker` does (rather than
+    /// `&mut self`) so the session's lock is dropped before joining the
+    /// worker thread - the worker locks `this` itself to dispatch a due
+    /// poll, so joining while still holding the guard would deadlock.
+    pub fn stop_poll_worker(this: &Arc<Mutex<Self>>) {
+        let worker = this.lock().unwrap().scheduler.begin_stop();
+        if let Some(worker) = worker {
+            let _ = worker.join();
+        }
+    }
+
+    /// Flashes `data` onto the ECU's memory at `mem`, using UDS's
+    /// RequestDownload / TransferData / RequestTransferExit block-transfer
+    /// services. `progress` is invoked after each block is written.
+    ///
+    /// Returns [`DiagError::NotSupported`] if the active session is KWP2000,
+    /// which has no equivalent service wired up here.
+    pub fn flash_firmware(
+        &mut self,
+        data_format_identifier: u8,
+        mem: uds::MemoryAddress,
+        data: &[u8],
+        progress: impl FnMut(uds::TransferProgress),
+    ) -> DiagServerResult<()> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(_) => Err(DiagError::NotSupported),
+            DynamicSessionType::Uds(u) => uds::download(u, data_format_identifier, mem, data, opts, progress),
+        }
+    }
+
+    /// Reads `mem.size` bytes of ECU memory starting at `mem.address`,
+    /// using UDS's RequestUpload / TransferData services. `progress` is
+    /// invoked after each block and may return `false` to abort, in which
+    /// case the bytes read so far are returned rather than discarded.
+    ///
+    /// Useful for extracting a crash dump without blocking on the whole
+    /// transfer completing.
+    ///
+    /// Returns [`DiagError::NotSupported`] if the active session is KWP2000,
+    /// which has no equivalent service wired up here.
+    pub fn read_memory(
+        &mut self,
+        data_format_identifier: u8,
+        mem: uds::MemoryAddress,
+        progress: impl FnMut(uds::TransferProgress) -> bool,
+    ) -> DiagServerResult<uds::UploadOutcome> {
+        let opts = self.retry_opts;
+        match self.session.borrow_mut() {
+            DynamicSessionType::Kwp(_) => Err(DiagError::NotSupported),
+            DynamicSessionType::Uds(u) => uds::upload(u, data_format_identifier, mem, opts, progress),
+        }
+    }
+}
\ No newline at end of file
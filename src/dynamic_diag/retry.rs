@@ -0,0 +1,149 @@
+//! Automatic handling of ECU-busy negative responses around a request
+//! dispatch: NRC 0x78 (RequestCorrectlyReceivedResponsePending) and NRC
+//! 0x21 (BusyRepeatRequest).
+//!
+//! Without this, a long-running operation (routine control, flashing, ...)
+//! surfaces these as a spurious error the first time the ECU asks for more
+//! time, instead of the real response once it's ready.
+
+use crate::{DiagError, DiagServerResult};
+
+/// NRC 0x78 - RequestCorrectlyReceivedResponsePending: the ECU is still
+/// working and will send the real response shortly.
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+/// NRC 0x21 - BusyRepeatRequest: the ECU was busy; resend the request.
+const NRC_BUSY_REPEAT_REQUEST: u8 = 0x21;
+
+/// Budgets for [`with_pending_retry`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingRetryOptions {
+    /// Maximum number of times a request is re-sent after a 0x78
+    /// ResponsePending reply before giving up and returning the error.
+    pub max_pending_retries: u32,
+    /// Maximum number of 0x21 BusyRepeatRequest resends before giving up.
+    pub max_repeat_retries: u32,
+    /// Read timeout applied on each retry attempt, in milliseconds.
+    pub per_attempt_timeout_ms: u32,
+}
+
+impl Default for PendingRetryOptions {
+    fn default() -> Self {
+        Self {
+            max_pending_retries: 10,
+            max_repeat_retries: 3,
+            per_attempt_timeout_ms: 1500,
+        }
+    }
+}
+
+fn nrc_code(err: &DiagError) -> Option<u8> {
+    match err {
+        DiagError::ECUError { code, .. } => Some(*code),
+        _ => None,
+    }
+}
+
+/// Runs `attempt` in a loop, transparently retrying while it fails with NRC
+/// 0x78 or 0x21, up to the budgets in `opts`. Both NRCs are handled the same
+/// way: `attempt` is called again, so the request is re-sent; a delayed
+/// response to the earlier request is not read separately. `attempt` is called with
+/// `true` once it's being retried, so it can widen the session's read
+/// timeout before resending - callers that do this are responsible for
+/// restoring the original timeout once this function returns, on every exit
+/// path, since it is otherwise left at `opts.per_attempt_timeout_ms` for
+/// every request afterwards.
+pub fn with_pending_retry<T>(
+    opts: PendingRetryOptions,
+    mut attempt: impl FnMut(bool) -> DiagServerResult<T>,
+) -> DiagServerResult<T> {
+    let mut pending_left = opts.max_pending_retries;
+    let mut repeat_left = opts.max_repeat_retries;
+    let mut is_retry = false;
+    loop {
+        match attempt(is_retry) {
+            Ok(v) => return Ok(v),
+            Err(e) => match nrc_code(&e) {
+                Some(NRC_RESPONSE_PENDING) if pending_left > 0 => {
+                    pending_left -= 1;
+                    is_retry = true;
+                }
+                Some(NRC_BUSY_REPEAT_REQUEST) if repeat_left > 0 => {
+                    repeat_left -= 1;
+                    is_retry = true;
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn succeeds_immediately_without_retrying() {
+        let calls = Cell::new(0u32);
+        let result: DiagServerResult<i32> = with_pending_retry(PendingRetryOptions::default(), |is_retry| {
+            calls.set(calls.get() + 1);
+            assert!(!is_retry);
+            Ok(42)
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn retries_response_pending_until_it_succeeds() {
+        let calls = Cell::new(0u32);
+        let result = with_pending_retry(PendingRetryOptions::default(), |is_retry| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                assert!(!is_retry);
+                Err(DiagError::ECUError { code: NRC_RESPONSE_PENDING, def: None })
+            } else {
+                assert!(is_retry);
+                Ok(7)
+            }
+        });
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn gives_up_once_the_pending_retry_budget_is_exhausted() {
+        let opts = PendingRetryOptions { max_pending_retries: 2, ..Default::default() };
+        let calls = Cell::new(0u32);
+        let result: DiagServerResult<()> = with_pending_retry(opts, |_| {
+            calls.set(calls.get() + 1);
+            Err(DiagError::ECUError { code: NRC_RESPONSE_PENDING, def: None })
+        });
+        assert!(result.is_err());
+        // The initial attempt, plus `max_pending_retries` retries.
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn gives_up_once_the_repeat_retry_budget_is_exhausted() {
+        let opts = PendingRetryOptions { max_repeat_retries: 1, ..Default::default() };
+        let calls = Cell::new(0u32);
+        let result: DiagServerResult<()> = with_pending_retry(opts, |_| {
+            calls.set(calls.get() + 1);
+            Err(DiagError::ECUError { code: NRC_BUSY_REPEAT_REQUEST, def: None })
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_a_non_busy_error() {
+        let calls = Cell::new(0u32);
+        let result: DiagServerResult<()> = with_pending_retry(PendingRetryOptions::default(), |_| {
+            calls.set(calls.get() + 1);
+            Err(DiagError::NotSupported)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
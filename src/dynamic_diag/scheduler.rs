@@ -0,0 +1,368 @@
+//! Background polling scheduler for [`DynamicDiagSession`](super::DynamicDiagSession)
+//!
+//! This lets a caller register a diagnostic request (for example a
+//! ReadDataByIdentifier, or a read-DTC-by-status poll) to be re-issued
+//! automatically at a fixed frequency, with results delivered via a callback,
+//! rather than having to hand-roll a timer loop around the session.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::DiagServerResult;
+
+use super::{DynamicDiagSession, DynamicSessionType};
+
+/// Highest recurring poll frequency that will be honoured.
+///
+/// Anything registered above this is silently clamped, so a handful of
+/// misconfigured entries can never oversubscribe the ISO-TP layer.
+const MAX_POLL_FREQUENCY_HZ: f32 = 10.0;
+
+/// Lowest recurring poll frequency that will be honoured. Guards against a
+/// zero or negative `frequency_hz`, which would otherwise turn into an
+/// infinite or negative period and panic `Duration::from_secs_f32`.
+const MIN_POLL_FREQUENCY_HZ: f32 = 0.01;
+
+/// Default number of requests the scheduler will allow in flight at once.
+const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// A function which builds and sends a request against the active session,
+/// returning the raw positive response payload.
+pub type PollBuilder = Box<dyn Fn(&mut DynamicSessionType) -> DiagServerResult<Vec<u8>> + Send>;
+
+/// Callback invoked with the result of each poll.
+pub type PollCallback = Box<dyn FnMut(DiagServerResult<Vec<u8>>) + Send>;
+
+/// Opaque handle to a registered poll, used to cancel it later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PollHandle(u64);
+
+/// A request to be (re)issued by the [`PollScheduler`].
+pub struct PollRequest {
+    build: PollBuilder,
+    callback: PollCallback,
+    /// `None` for a one-shot request.
+    frequency_hz: Option<f32>,
+    /// Identity used to de-duplicate pending one-shot requests. Ignored for
+    /// recurring requests.
+    dedup_key: Option<Vec<u8>>,
+}
+
+impl PollRequest {
+    /// Creates a recurring request, re-issued at `frequency_hz` (clamped to
+    /// [`MAX_POLL_FREQUENCY_HZ`]).
+    pub fn recurring(
+        frequency_hz: f32,
+        build: impl Fn(&mut DynamicSessionType) -> DiagServerResult<Vec<u8>> + Send + 'static,
+        callback: impl FnMut(DiagServerResult<Vec<u8>>) + Send + 'static,
+    ) -> Self {
+        Self {
+            build: Box::new(build),
+            callback: Box::new(callback),
+            frequency_hz: Some(frequency_hz.clamp(MIN_POLL_FREQUENCY_HZ, MAX_POLL_FREQUENCY_HZ)),
+            dedup_key: None,
+        }
+    }
+
+    /// Creates a one-shot request. If `dedup_key` matches a request that is
+    /// already pending, this call is dropped rather than queued twice.
+    pub fn one_shot(
+        dedup_key: Vec<u8>,
+        build: impl Fn(&mut DynamicSessionType) -> DiagServerResult<Vec<u8>> + Send + 'static,
+        callback: impl FnMut(DiagServerResult<Vec<u8>>) + Send + 'static,
+    ) -> Self {
+        Self {
+            build: Box::new(build),
+            callback: Box::new(callback),
+            frequency_hz: None,
+            dedup_key: Some(dedup_key),
+        }
+    }
+}
+
+struct Entry {
+    req: PollRequest,
+    last_sent: Option<Instant>,
+}
+
+struct SchedulerState {
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+    in_flight: usize,
+}
+
+/// Registry of polling entries plus (once started) the worker thread that
+/// drains them.
+///
+/// Registering a [`PollRequest`] only adds it to the registry; call
+/// [`PollScheduler::spawn_worker`] once to start dispatching due entries in
+/// the background.
+pub struct PollScheduler {
+    state: Arc<Mutex<SchedulerState>>,
+    wake: Arc<Condvar>,
+    max_in_flight: usize,
+    shutdown: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for PollScheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PollScheduler")
+            .field("max_in_flight", &self.max_in_flight)
+            .field("running", &self.worker.is_some())
+            .finish()
+    }
+}
+
+impl Default for PollScheduler {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SchedulerState {
+                entries: HashMap::new(),
+                next_id: 0,
+                in_flight: 0,
+            })),
+            wake: Arc::new(Condvar::new()),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            worker: None,
+        }
+    }
+}
+
+impl PollScheduler {
+    /// Sets the maximum number of requests the scheduler will allow in
+    /// flight simultaneously. Must be called before [`Self::spawn_worker`].
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    /// Registers a new poll. One-shot requests whose `dedup_key` matches an
+    /// already-pending request are dropped, and [`None`] is returned.
+    pub fn register(&self, req: PollRequest) -> Option<PollHandle> {
+        let mut state = self.state.lock().unwrap();
+        if req.frequency_hz.is_none() {
+            if let Some(key) = &req.dedup_key {
+                let already_pending = state
+                    .entries
+                    .values()
+                    .any(|e| e.req.dedup_key.as_ref() == Some(key));
+                if already_pending {
+                    return None;
+                }
+            }
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.entries.insert(
+            id,
+            Entry {
+                req,
+                last_sent: None,
+            },
+        );
+        drop(state);
+        self.wake.notify_all();
+        Some(PollHandle(id))
+    }
+
+    /// Cancels a registered poll. Returns `false` if the handle is unknown
+    /// (it may already have completed, if it was one-shot).
+    pub fn cancel(&self, handle: PollHandle) -> bool {
+        let removed = self.state.lock().unwrap().entries.remove(&handle.0).is_some();
+        self.wake.notify_all();
+        removed
+    }
+
+    /// Starts the background worker which wakes on the nearest due entry,
+    /// dispatches it against `session`, and invokes its callback with the
+    /// result. Calling [`Self::stop`] (or dropping the scheduler) ends the
+    /// worker.
+    pub fn spawn_worker(&mut self, session: Arc<Mutex<DynamicDiagSession>>) {
+        if self.worker.is_some() {
+            return;
+        }
+        let state = self.state.clone();
+        let wake = self.wake.clone();
+        let shutdown = self.shutdown.clone();
+        let max_in_flight = self.max_in_flight;
+        self.worker = Some(std::thread::spawn(move || {
+            run_worker(session, state, wake, shutdown, max_in_flight);
+        }));
+    }
+
+    /// Signals the worker thread (if any) to stop and hands back its
+    /// handle, without joining it.
+    ///
+    /// Split out from [`Self::stop`] so a caller holding the session's
+    /// `Mutex` guard can drop it *before* joining: the worker locks that
+    /// same `Mutex` to dispatch a due poll, so joining while still holding
+    /// the guard would deadlock.
+    pub(super) fn begin_stop(&mut self) -> Option<JoinHandle<()>> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake.notify_all();
+        self.worker.take()
+    }
+
+    /// Stops the worker thread started by [`Self::spawn_worker`], if any.
+    pub fn stop(&mut self) {
+        if let Some(worker) = self.begin_stop() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for PollScheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop_build(_: &mut DynamicSessionType) -> DiagServerResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    #[test]
+    fn recurring_clamps_frequency_to_the_max() {
+        let req = PollRequest::recurring(1_000.0, noop_build, |_| {});
+        assert_eq!(req.frequency_hz, Some(MAX_POLL_FREQUENCY_HZ));
+    }
+
+    #[test]
+    fn recurring_clamps_frequency_to_the_min() {
+        // A zero or negative frequency would otherwise turn into an
+        // infinite or negative period and panic `Duration::from_secs_f32`
+        // in `run_worker`.
+        for frequency_hz in [0.0, -5.0, f32::NEG_INFINITY] {
+            let req = PollRequest::recurring(frequency_hz, noop_build, |_| {});
+            assert_eq!(req.frequency_hz, Some(MIN_POLL_FREQUENCY_HZ));
+        }
+    }
+
+    #[test]
+    fn recurring_leaves_an_in_range_frequency_untouched() {
+        let req = PollRequest::recurring(5.0, noop_build, |_| {});
+        assert_eq!(req.frequency_hz, Some(5.0));
+    }
+
+    #[test]
+    fn register_dedups_pending_one_shot_requests() {
+        let scheduler = PollScheduler::default();
+        let first = scheduler.register(PollRequest::one_shot(vec![1, 2, 3], noop_build, |_| {}));
+        assert!(first.is_some());
+
+        let second = scheduler.register(PollRequest::one_shot(vec![1, 2, 3], noop_build, |_| {}));
+        assert!(second.is_none(), "duplicate dedup_key should be dropped");
+
+        let third = scheduler.register(PollRequest::one_shot(vec![9, 9, 9], noop_build, |_| {}));
+        assert!(third.is_some(), "a distinct dedup_key should still register");
+    }
+
+    #[test]
+    fn cancel_removes_a_registered_poll_and_is_idempotent() {
+        let scheduler = PollScheduler::default();
+        let handle = scheduler
+            .register(PollRequest::recurring(1.0, noop_build, |_| {}))
+            .unwrap();
+        assert!(scheduler.cancel(handle));
+        assert!(!scheduler.cancel(handle), "cancelling twice should report false");
+    }
+}
+
+fn run_worker(
+    session: Arc<Mutex<DynamicDiagSession>>,
+    state: Arc<Mutex<SchedulerState>>,
+    wake: Arc<Condvar>,
+    shutdown: Arc<AtomicBool>,
+    max_in_flight: usize,
+) {
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let (due_ids, next_wake) = {
+            let guard = state.lock().unwrap();
+            let now = Instant::now();
+            let mut due = Vec::new();
+            let mut soonest: Option<Duration> = None;
+            for (id, entry) in guard.entries.iter() {
+                let due_at = match entry.last_sent {
+                    None => now,
+                    Some(last) => {
+                        let period = Duration::from_secs_f32(
+                            1.0 / entry.req.frequency_hz.unwrap_or(MAX_POLL_FREQUENCY_HZ),
+                        );
+                        last + period
+                    }
+                };
+                if due_at <= now {
+                    due.push(*id);
+                } else {
+                    let remaining = due_at - now;
+                    soonest = Some(soonest.map_or(remaining, |s| s.min(remaining)));
+                }
+            }
+            (due, soonest)
+        };
+
+        if due_ids.is_empty() {
+            let guard = state.lock().unwrap();
+            let _ = match next_wake {
+                Some(d) => wake.wait_timeout(guard, d).unwrap().0,
+                None => wake.wait_timeout(guard, Duration::from_millis(250)).unwrap().0,
+            };
+            continue;
+        }
+
+        for id in due_ids {
+            {
+                let mut guard = state.lock().unwrap();
+                while guard.in_flight >= max_in_flight {
+                    guard = wake.wait_timeout(guard, Duration::from_millis(50)).unwrap().0;
+                    if shutdown.load(Ordering::SeqCst) {
+                        return;
+                    }
+                }
+                guard.in_flight += 1;
+            }
+
+            let mut entry = {
+                let mut guard = state.lock().unwrap();
+                match guard.entries.remove(&id) {
+                    Some(e) => e,
+                    None => {
+                        guard.in_flight -= 1;
+                        continue;
+                    }
+                }
+            };
+            entry.last_sent = Some(Instant::now());
+
+            let result = {
+                let mut sess = session.lock().unwrap();
+                (entry.req.build)(&mut sess.session)
+            };
+            (entry.req.callback)(result);
+
+            let mut guard = state.lock().unwrap();
+            guard.in_flight -= 1;
+            if entry.req.frequency_hz.is_some() {
+                guard.entries.insert(id, entry);
+            }
+            drop(guard);
+            wake.notify_all();
+        }
+    }
+}
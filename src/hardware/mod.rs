@@ -0,0 +1,8 @@
+//! Hardware communication backends used to open an [`IsoTPChannel`](crate::channel::IsoTPChannel)
+//! to an ECU.
+
+mod passthru;
+mod socketcan;
+
+pub use passthru::*;
+pub use socketcan::*;
@@ -0,0 +1,408 @@
+//! J2534 PassThru hardware backend
+//!
+//! Loads a vendor-supplied PassThru DLL/shared library at runtime and
+//! drives it according to SAE J2534-1, so the crate's KWP2000/UDS servers
+//! can run unchanged over any commodity PassThru VCI rather than only the
+//! hardware backends built against a specific vendor SDK.
+
+use std::{
+    ffi::c_void,
+    sync::{Arc, Mutex},
+};
+
+use libloading::{Library, Symbol};
+
+use crate::{
+    channel::{IsoTPChannel, IsoTPSettings, PayloadChannel},
+    hardware::{Hardware, HardwareError, HardwareInfo, HardwareResult},
+    DiagServerResult,
+};
+
+/// J2534 protocol ID for ISO15765 (ISO-TP over CAN).
+const ISO15765: u32 = 6;
+
+/// `TxFlag`/`RxStatus` bit marking a 29-bit (extended) CAN ID.
+const CAN_29BIT_ID: u32 = 0x100;
+
+/// J2534 IOCTL used to push flow-control filter / STmin/BS configuration
+/// onto an already-open channel.
+const SET_CONFIG: u32 = 1;
+
+/// J2534 filter type used to admit only frames from the ECU's response ID
+/// and reply with the flow-control frame the ECU expects.
+const FLOW_CONTROL_FILTER: u32 = 3;
+
+/// `SCONFIG` parameter IDs from the J2534-1 ISO15765 configuration table.
+const PARAM_ISO15765_BS: u32 = 0x18;
+const PARAM_ISO15765_STMIN: u32 = 0x19;
+const PARAM_ISO15765_BS_TX: u32 = 0x1E;
+const PARAM_ISO15765_STMIN_TX: u32 = 0x1F;
+const PARAM_ISO15765_PAD_VALUE: u32 = 0x16;
+const PARAM_DATA_PADDING: u32 = 0x15;
+
+/// Maximum payload of a single `PASSTHRU_MSG`, per J2534-1.
+const PASSTHRU_MSG_DATA_SIZE: usize = 4128;
+
+/// Mirrors the vendor `PASSTHRU_MSG` struct layout (J2534-1 §8.3). Used for
+/// both ISO-TP traffic (`ReadMsgs`/`WriteMsgs`) and filter mask/pattern/flow
+/// control messages (`StartMsgFilter`).
+#[repr(C)]
+struct PassThruMsg {
+    protocol_id: u32,
+    rx_status: u32,
+    tx_flags: u32,
+    timestamp: u32,
+    data_size: u32,
+    extra_data_index: u32,
+    data: [u8; PASSTHRU_MSG_DATA_SIZE],
+}
+
+impl PassThruMsg {
+    /// Builds a message carrying `id` (as the leading 4 bytes, standard or
+    /// extended) followed by `payload`.
+    fn for_id(id: u32, payload: &[u8]) -> Self {
+        let mut data = [0u8; PASSTHRU_MSG_DATA_SIZE];
+        data[0..4].copy_from_slice(&id.to_be_bytes());
+        data[4..4 + payload.len()].copy_from_slice(payload);
+        Self {
+            protocol_id: ISO15765,
+            rx_status: 0,
+            tx_flags: if id > 0x7FF { CAN_29BIT_ID } else { 0 },
+            timestamp: 0,
+            data_size: 4 + payload.len() as u32,
+            extra_data_index: 0,
+            data,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            protocol_id: ISO15765,
+            rx_status: 0,
+            tx_flags: 0,
+            timestamp: 0,
+            data_size: 0,
+            extra_data_index: 0,
+            data: [0u8; PASSTHRU_MSG_DATA_SIZE],
+        }
+    }
+
+    /// The message's data, stripped of the leading 4-byte CAN ID.
+    fn payload(&self) -> Vec<u8> {
+        let size = self.data_size as usize;
+        if size <= 4 {
+            Vec::new()
+        } else {
+            self.data[4..size.min(PASSTHRU_MSG_DATA_SIZE)].to_vec()
+        }
+    }
+}
+
+/// A single `{parameter, value}` pair in an `SCONFIG_LIST`.
+#[repr(C)]
+struct SConfig {
+    parameter: u32,
+    value: u32,
+}
+
+/// Mirrors the vendor `SCONFIG_LIST` struct passed to `PassThruIoctl` /
+/// `SET_CONFIG`.
+#[repr(C)]
+struct SConfigList {
+    num_of_params: u32,
+    config_ptr: *mut SConfig,
+}
+
+type PtOpen = unsafe extern "stdcall" fn(*const c_void, *mut u32) -> i32;
+type PtClose = unsafe extern "stdcall" fn(u32) -> i32;
+type PtConnect = unsafe extern "stdcall" fn(u32, u32, u32, u32, *mut u32) -> i32;
+type PtDisconnect = unsafe extern "stdcall" fn(u32) -> i32;
+type PtReadMsgs = unsafe extern "stdcall" fn(u32, *mut c_void, *mut u32, u32) -> i32;
+type PtWriteMsgs = unsafe extern "stdcall" fn(u32, *const c_void, *mut u32, u32) -> i32;
+type PtStartMsgFilter =
+    unsafe extern "stdcall" fn(u32, u32, *const c_void, *const c_void, *const c_void, *mut u32) -> i32;
+type PtIoctl = unsafe extern "stdcall" fn(u32, u32, *mut c_void, *mut c_void) -> i32;
+type PtGetLastError = unsafe extern "stdcall" fn(*mut i8) -> i32;
+
+/// The set of PassThru entry points we need out of the vendor DLL. Kept
+/// alongside the loaded [`Library`] so the symbols outlive any one call.
+struct PassthruApi {
+    _lib: Library,
+    open: PtOpen,
+    close: PtClose,
+    connect: PtConnect,
+    disconnect: PtDisconnect,
+    read_msgs: PtReadMsgs,
+    write_msgs: PtWriteMsgs,
+    start_msg_filter: PtStartMsgFilter,
+    ioctl: PtIoctl,
+    get_last_error: PtGetLastError,
+}
+
+impl PassthruApi {
+    /// Loads `dll_path` and resolves the subset of the J2534 API this
+    /// backend uses.
+    unsafe fn load(dll_path: &str) -> HardwareResult<Self> {
+        let lib = Library::new(dll_path).map_err(|e| HardwareError::DeviceOpenError(e.to_string()))?;
+        macro_rules! sym {
+            ($name:literal) => {
+                *lib.get::<Symbol<_>>($name.as_bytes())
+                    .map_err(|e| HardwareError::DeviceOpenError(e.to_string()))?
+            };
+        }
+        Ok(Self {
+            open: sym!(b"PassThruOpen"),
+            close: sym!(b"PassThruClose"),
+            connect: sym!(b"PassThruConnect"),
+            disconnect: sym!(b"PassThruDisconnect"),
+            read_msgs: sym!(b"PassThruReadMsgs"),
+            write_msgs: sym!(b"PassThruWriteMsgs"),
+            start_msg_filter: sym!(b"PassThruStartMsgFilter"),
+            ioctl: sym!(b"PassThruIoctl"),
+            get_last_error: sym!(b"PassThruGetLastError"),
+            _lib: lib,
+        })
+    }
+
+    /// Reads the vendor's last-error string, for attaching context to a
+    /// failed call.
+    unsafe fn last_error(&self) -> String {
+        let mut buf = [0i8; 80];
+        if (self.get_last_error)(buf.as_mut_ptr()) == 0 {
+            let cstr = std::ffi::CStr::from_ptr(buf.as_ptr());
+            cstr.to_string_lossy().into_owned()
+        } else {
+            "unknown PassThru error".into()
+        }
+    }
+}
+
+/// A J2534 PassThru device, opened against a vendor DLL.
+pub struct PassthruDevice {
+    api: Arc<PassthruApi>,
+    device_id: u32,
+    info: HardwareInfo,
+}
+
+impl std::fmt::Debug for PassthruDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PassthruDevice")
+            .field("device_id", &self.device_id)
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+impl PassthruDevice {
+    /// Loads the vendor DLL at `dll_path` and opens the device it exposes.
+    pub fn open(dll_path: &str) -> HardwareResult<Self> {
+        unsafe {
+            let api = PassthruApi::load(dll_path)?;
+            let mut device_id = 0u32;
+            if (api.open)(std::ptr::null(), &mut device_id) != 0 {
+                return Err(HardwareError::DeviceOpenError(api.last_error()));
+            }
+            Ok(Self {
+                api: Arc::new(api),
+                device_id,
+                info: HardwareInfo {
+                    name: dll_path.to_string(),
+                    vendor: "J2534 PassThru".into(),
+                },
+            })
+        }
+    }
+}
+
+impl Drop for PassthruDevice {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.close)(self.device_id);
+        }
+    }
+}
+
+impl Hardware for PassthruDevice {
+    fn create_iso_tp_channel(
+        hw_device: Arc<Mutex<Self>>,
+    ) -> DiagServerResult<Box<dyn IsoTPChannel>> {
+        Ok(Box::new(PassthruIsoTpChannel {
+            device: hw_device,
+            channel_id: None,
+            send_id: 0,
+        }))
+    }
+
+    fn get_info(&self) -> &HardwareInfo {
+        &self.info
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+/// An open ISO15765 channel on a [`PassthruDevice`].
+pub struct PassthruIsoTpChannel {
+    device: Arc<Mutex<PassthruDevice>>,
+    channel_id: Option<u32>,
+    /// Tester (tx) CAN ID, set by [`PayloadChannel::set_ids`] and used to
+    /// address outgoing [`PassThruMsg`]s.
+    send_id: u32,
+}
+
+impl PassthruIsoTpChannel {
+    fn api(&self) -> Arc<PassthruApi> {
+        self.device.lock().unwrap().api.clone()
+    }
+}
+
+impl PayloadChannel for PassthruIsoTpChannel {
+    fn open(&mut self) -> DiagServerResult<()> {
+        let device = self.device.lock().unwrap();
+        let api = device.api.clone();
+        let mut channel_id = 0u32;
+        let res = unsafe {
+            (api.connect)(
+                device.device_id,
+                ISO15765,
+                0, // CAN_ID_BOTH / default flags
+                500_000,
+                &mut channel_id,
+            )
+        };
+        if res != 0 {
+            return Err(crate::DiagError::NotSupported);
+        }
+        drop(device);
+        self.channel_id = Some(channel_id);
+        Ok(())
+    }
+
+    fn close(&mut self) -> DiagServerResult<()> {
+        if let Some(channel_id) = self.channel_id.take() {
+            unsafe {
+                (self.api().disconnect)(channel_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_ids(&mut self, send: u32, recv: u32) -> DiagServerResult<()> {
+        let channel_id = self.channel_id.ok_or(crate::DiagError::NotSupported)?;
+        let api = self.api();
+
+        // A FLOW_CONTROL_FILTER admits frames matching `recv` (the ECU's
+        // response ID) and tells the vendor DLL to answer their flow
+        // control with `send` (our tester ID) - the mask is all-ones since
+        // we want an exact match on `recv`.
+        let mask = PassThruMsg::for_id(0xFFFF_FFFF, &[]);
+        let pattern = PassThruMsg::for_id(recv, &[]);
+        let flow_control = PassThruMsg::for_id(send, &[]);
+        let mut filter_id = 0u32;
+        let res = unsafe {
+            (api.start_msg_filter)(
+                channel_id,
+                FLOW_CONTROL_FILTER,
+                &mask as *const PassThruMsg as *const c_void,
+                &pattern as *const PassThruMsg as *const c_void,
+                &flow_control as *const PassThruMsg as *const c_void,
+                &mut filter_id,
+            )
+        };
+        if res != 0 {
+            return Err(crate::DiagError::NotSupported);
+        }
+        self.send_id = send;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, timeout_ms: u32) -> DiagServerResult<Vec<u8>> {
+        let channel_id = self.channel_id.ok_or(crate::DiagError::NotSupported)?;
+        let api = self.api();
+        let mut msg = PassThruMsg::empty();
+        let mut num_msgs = 1u32;
+        let res = unsafe {
+            (api.read_msgs)(
+                channel_id,
+                &mut msg as *mut PassThruMsg as *mut c_void,
+                &mut num_msgs,
+                timeout_ms,
+            )
+        };
+        if res != 0 || num_msgs == 0 {
+            return Err(crate::DiagError::NotSupported);
+        }
+        Ok(msg.payload())
+    }
+
+    fn write_bytes(&mut self, _addr: u32, buffer: &[u8], timeout_ms: u32) -> DiagServerResult<()> {
+        let channel_id = self.channel_id.ok_or(crate::DiagError::NotSupported)?;
+        let api = self.api();
+        let msg = PassThruMsg::for_id(self.send_id, buffer);
+        let mut num_msgs = 1u32;
+        let res = unsafe {
+            (api.write_msgs)(
+                channel_id,
+                &msg as *const PassThruMsg as *const c_void,
+                &mut num_msgs,
+                timeout_ms,
+            )
+        };
+        if res != 0 {
+            return Err(crate::DiagError::NotSupported);
+        }
+        Ok(())
+    }
+}
+
+impl IsoTPChannel for PassthruIsoTpChannel {
+    fn set_iso_tp_cfg(&mut self, cfg: IsoTPSettings) -> DiagServerResult<()> {
+        let channel_id = self.channel_id.ok_or(crate::DiagError::NotSupported)?;
+        let api = self.api();
+
+        let mut params = [
+            SConfig {
+                parameter: PARAM_ISO15765_BS,
+                value: cfg.block_size as u32,
+            },
+            SConfig {
+                parameter: PARAM_ISO15765_BS_TX,
+                value: cfg.block_size as u32,
+            },
+            SConfig {
+                parameter: PARAM_ISO15765_STMIN,
+                value: cfg.st_min_ms as u32,
+            },
+            SConfig {
+                parameter: PARAM_ISO15765_STMIN_TX,
+                value: cfg.st_min_ms as u32,
+            },
+            SConfig {
+                parameter: PARAM_DATA_PADDING,
+                value: cfg.pad_frame as u32,
+            },
+            SConfig {
+                parameter: PARAM_ISO15765_PAD_VALUE,
+                value: 0xCC,
+            },
+        ];
+        let mut list = SConfigList {
+            num_of_params: params.len() as u32,
+            config_ptr: params.as_mut_ptr(),
+        };
+
+        let res = unsafe {
+            (api.ioctl)(
+                channel_id,
+                SET_CONFIG,
+                &mut list as *mut SConfigList as *mut c_void,
+                std::ptr::null_mut(),
+            )
+        };
+        if res != 0 {
+            return Err(crate::DiagError::NotSupported);
+        }
+        Ok(())
+    }
+}
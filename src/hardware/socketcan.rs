@@ -0,0 +1,312 @@
+//! SocketCAN hardware backend for Linux
+//!
+//! Opens a `can0`-style SocketCAN interface so the crate's diagnostic
+//! servers can run on embedded Linux gateways and bench setups without a
+//! proprietary VCI. Prefers the kernel's `can-isotp` socket type, which
+//! does ISO-TP segmentation/flow-control in the kernel; falls back to a
+//! raw CAN(-FD) socket where `can-isotp` isn't loaded.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use socketcan::{CanFdFrame, CanFrame, CanFdSocket, CanSocket, Frame, Socket};
+use socketcan_isotp::{ExtendedId, FlowControlOptions, IsoTpOptions, IsoTpSocket, StandardId};
+
+use crate::{
+    channel::{IsoTPChannel, IsoTPSettings, PayloadChannel},
+    hardware::{Hardware, HardwareError, HardwareInfo, HardwareResult},
+    DiagError, DiagServerResult,
+};
+
+/// A SocketCAN network interface (for example `can0` or `vcan0`).
+#[derive(Debug)]
+pub struct SocketCanDevice {
+    interface: String,
+    /// Whether to negotiate CAN-FD frames on the raw-socket fallback path.
+    use_can_fd: bool,
+    info: HardwareInfo,
+}
+
+impl SocketCanDevice {
+    /// Opens `interface` (for example `can0`), verifying it exists by
+    /// briefly binding a raw CAN socket to it.
+    pub fn open(interface: &str, use_can_fd: bool) -> HardwareResult<Self> {
+        CanSocket::open(interface).map_err(|e| HardwareError::DeviceOpenError(e.to_string()))?;
+        Ok(Self {
+            interface: interface.to_string(),
+            use_can_fd,
+            info: HardwareInfo {
+                name: interface.to_string(),
+                vendor: "SocketCAN".into(),
+            },
+        })
+    }
+}
+
+impl Hardware for SocketCanDevice {
+    fn create_iso_tp_channel(
+        hw_device: Arc<Mutex<Self>>,
+    ) -> DiagServerResult<Box<dyn IsoTPChannel>> {
+        Ok(Box::new(SocketCanIsoTpChannel {
+            device: hw_device,
+            isotp_socket: None,
+            raw_socket: None,
+            raw_tx_id: 0,
+            raw_rx_id: 0,
+            raw_pad: false,
+        }))
+    }
+
+    fn get_info(&self) -> &HardwareInfo {
+        &self.info
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+/// An ISO-TP channel on a [`SocketCanDevice`]. Backed by a kernel
+/// `can-isotp` socket when available, or a raw CAN(-FD) socket otherwise -
+/// in the raw-socket case only single-frame traffic is supported here, as
+/// this crate does not re-implement ISO-TP segmentation in userspace.
+pub struct SocketCanIsoTpChannel {
+    device: Arc<Mutex<SocketCanDevice>>,
+    isotp_socket: Option<IsoTpSocket>,
+    raw_socket: Option<RawCanSocket>,
+    /// CAN ID frames are sent under when only `raw_socket` is open (the
+    /// kernel `can-isotp` socket binds its own tx/rx IDs at open time, so it
+    /// doesn't need these).
+    raw_tx_id: u32,
+    raw_rx_id: u32,
+    /// Whether to pad single-frame payloads out to the full frame length.
+    raw_pad: bool,
+}
+
+enum RawCanSocket {
+    Classic(CanSocket),
+    Fd(CanFdSocket),
+}
+
+fn can_id(id: u32) -> socketcan_isotp::Id {
+    if id > 0x7FF {
+        socketcan_isotp::Id::Extended(ExtendedId::new(id).expect("valid 29-bit CAN ID"))
+    } else {
+        socketcan_isotp::Id::Standard(StandardId::new(id as u16).expect("valid 11-bit CAN ID"))
+    }
+}
+
+/// Builds the raw-socket equivalent of [`can_id`], for the `raw_socket`
+/// fallback path (`socketcan`'s `Id` rather than `socketcan_isotp`'s).
+fn raw_can_id(id: u32) -> socketcan::Id {
+    if id > 0x7FF {
+        socketcan::Id::Extended(socketcan::ExtendedId::new(id).expect("valid 29-bit CAN ID"))
+    } else {
+        socketcan::Id::Standard(socketcan::StandardId::new(id as u16).expect("valid 11-bit CAN ID"))
+    }
+}
+
+/// Largest single-frame payload a classic CAN frame can carry: a 1-byte PCI
+/// leaves 7 of the frame's 8 data bytes free.
+const RAW_SF_MAX_CLASSIC: usize = 7;
+
+/// Largest single-frame payload a CAN-FD frame can carry using the
+/// ISO15765-2 escape sequence (PCI `0x00` + a dedicated length byte), out of
+/// its 64 data bytes.
+const RAW_SF_MAX_FD: usize = 62;
+
+/// Encodes `payload` as an ISO15765-2 single frame: the direct `0x0N` PCI
+/// form for `payload.len() <= 7`, or (CAN-FD only) the escape form - PCI
+/// `0x00` followed by a dedicated length byte - for longer payloads, which
+/// the direct form's 4-bit length nibble can't address.
+fn encode_single_frame(payload: &[u8], is_fd: bool) -> DiagServerResult<Vec<u8>> {
+    let max_payload = if is_fd { RAW_SF_MAX_FD } else { RAW_SF_MAX_CLASSIC };
+    if payload.len() > max_payload {
+        // Multi-frame ISO-TP segmentation isn't implemented over this
+        // fallback; only a payload that fits in a single frame can be sent
+        // without `can-isotp`.
+        return Err(DiagError::NotSupported);
+    }
+    let mut data = Vec::with_capacity(payload.len() + 2);
+    if payload.len() <= RAW_SF_MAX_CLASSIC {
+        data.push(payload.len() as u8);
+    } else {
+        data.push(0x00);
+        data.push(payload.len() as u8);
+    }
+    data.extend_from_slice(payload);
+    Ok(data)
+}
+
+/// Decodes an ISO15765-2 single frame produced by [`encode_single_frame`].
+/// Rejects anything that isn't a single frame (the high PCI nibble is
+/// nonzero), since this fallback can't reassemble multi-frame traffic.
+fn decode_single_frame(data: &[u8], is_fd: bool) -> DiagServerResult<Vec<u8>> {
+    let pci = *data.first().ok_or(DiagError::NotSupported)?;
+    if pci & 0xF0 != 0x00 {
+        return Err(DiagError::NotSupported);
+    }
+    if is_fd && pci == 0x00 && data.len() > 1 {
+        let len = *data.get(1).ok_or(DiagError::NotSupported)? as usize;
+        return data.get(2..2 + len).map(<[u8]>::to_vec).ok_or(DiagError::NotSupported);
+    }
+    let len = (pci & 0x0F) as usize;
+    data.get(1..1 + len).map(<[u8]>::to_vec).ok_or(DiagError::NotSupported)
+}
+
+impl PayloadChannel for SocketCanIsoTpChannel {
+    fn open(&mut self) -> DiagServerResult<()> {
+        let device = self.device.lock().unwrap();
+        match IsoTpSocket::open(&device.interface, can_id(0), can_id(0)) {
+            Ok(socket) => {
+                self.isotp_socket = Some(socket);
+            }
+            Err(_) => {
+                // can-isotp kernel module not loaded - fall back to raw CAN.
+                self.raw_socket = Some(if device.use_can_fd {
+                    RawCanSocket::Fd(
+                        CanFdSocket::open(&device.interface)
+                            .map_err(|_| DiagError::NotSupported)?,
+                    )
+                } else {
+                    RawCanSocket::Classic(
+                        CanSocket::open(&device.interface).map_err(|_| DiagError::NotSupported)?,
+                    )
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> DiagServerResult<()> {
+        self.isotp_socket = None;
+        self.raw_socket = None;
+        Ok(())
+    }
+
+    fn set_ids(&mut self, send: u32, recv: u32) -> DiagServerResult<()> {
+        let device = self.device.lock().unwrap();
+        if self.isotp_socket.is_some() {
+            // SocketCAN binds tx/rx IDs at socket-open time, so re-bind.
+            self.isotp_socket = Some(
+                IsoTpSocket::open(&device.interface, can_id(send), can_id(recv))
+                    .map_err(|_| DiagError::NotSupported)?,
+            );
+        }
+        // Kept even when only `raw_socket` is open - there's no socket to
+        // re-bind, but `read_bytes`/`write_bytes` need the IDs to frame and
+        // filter raw CAN(-FD) frames themselves.
+        self.raw_tx_id = send;
+        self.raw_rx_id = recv;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, timeout_ms: u32) -> DiagServerResult<Vec<u8>> {
+        if let Some(socket) = &mut self.isotp_socket {
+            socket
+                .set_read_timeout(std::time::Duration::from_millis(timeout_ms as u64))
+                .map_err(|_| DiagError::NotSupported)?;
+            let mut buf = [0u8; 4095];
+            let n = socket.read(&mut buf).map_err(|_| DiagError::NotSupported)?;
+            return Ok(buf[..n].to_vec());
+        }
+        let raw = self.raw_socket.as_ref().ok_or(DiagError::NotSupported)?;
+        let is_fd = matches!(raw, RawCanSocket::Fd(_));
+        let expected_id = raw_can_id(self.raw_rx_id);
+        let overall_timeout = Duration::from_millis(timeout_ms as u64);
+        let deadline = Instant::now() + overall_timeout;
+        let data = loop {
+            // Each read only waits out the time left of `timeout_ms` as a
+            // whole - otherwise bus traffic for other IDs, which gets
+            // filtered out and re-read below, could make a single
+            // `read_bytes` call block for many multiples of the caller's
+            // requested timeout.
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or(DiagError::NotSupported)?;
+            let (frame_id, frame_data) = match raw {
+                RawCanSocket::Classic(socket) => {
+                    socket
+                        .set_read_timeout(remaining)
+                        .map_err(|_| DiagError::NotSupported)?;
+                    let frame = socket.read_frame().map_err(|_| DiagError::NotSupported)?;
+                    (frame.id(), frame.data().to_vec())
+                }
+                RawCanSocket::Fd(socket) => {
+                    socket
+                        .set_read_timeout(remaining)
+                        .map_err(|_| DiagError::NotSupported)?;
+                    let frame = socket.read_frame().map_err(|_| DiagError::NotSupported)?;
+                    (frame.id(), frame.data().to_vec())
+                }
+            };
+            // There's no kernel-side filter on this fallback path (unlike the
+            // `can-isotp` socket, which binds its rx ID at open time), so
+            // filter by hand - otherwise any other traffic on the bus would
+            // be mistaken for a response.
+            if frame_id == expected_id {
+                break frame_data;
+            }
+        };
+        decode_single_frame(&data, is_fd)
+    }
+
+    fn write_bytes(&mut self, _addr: u32, buffer: &[u8], timeout_ms: u32) -> DiagServerResult<()> {
+        if let Some(socket) = &mut self.isotp_socket {
+            socket
+                .set_write_timeout(std::time::Duration::from_millis(timeout_ms as u64))
+                .map_err(|_| DiagError::NotSupported)?;
+            socket.write(buffer).map_err(|_| DiagError::NotSupported)?;
+            return Ok(());
+        }
+        let raw = self.raw_socket.as_ref().ok_or(DiagError::NotSupported)?;
+        let is_fd = matches!(raw, RawCanSocket::Fd(_));
+        let mut data = encode_single_frame(buffer, is_fd)?;
+        if self.raw_pad {
+            data.resize(if is_fd { 64 } else { 8 }, 0xCC);
+        }
+        let id = raw_can_id(self.raw_tx_id);
+        let _ = timeout_ms; // raw CAN sockets write synchronously; no timeout to apply.
+        match raw {
+            RawCanSocket::Classic(socket) => {
+                let frame = CanFrame::new(id, &data).ok_or(DiagError::NotSupported)?;
+                socket.write_frame(&frame).map_err(|_| DiagError::NotSupported)
+            }
+            RawCanSocket::Fd(socket) => {
+                let frame = CanFdFrame::new(id, &data).ok_or(DiagError::NotSupported)?;
+                socket.write_frame(&frame).map_err(|_| DiagError::NotSupported)
+            }
+        }
+    }
+}
+
+impl IsoTPChannel for SocketCanIsoTpChannel {
+    fn set_iso_tp_cfg(&mut self, cfg: IsoTPSettings) -> DiagServerResult<()> {
+        if let Some(socket) = self.isotp_socket.as_mut() {
+            socket
+                .set_opts(
+                    IsoTpOptions::default().set_extended_addressing(cfg.extended_addressing),
+                )
+                .map_err(|_| DiagError::NotSupported)?;
+            return socket
+                .set_fc_opts(
+                    FlowControlOptions::default()
+                        .set_bs(cfg.block_size as u8)
+                        .set_stmin(cfg.st_min_ms as u8)
+                        .set_pad(cfg.pad_frame),
+                )
+                .map_err(|_| DiagError::NotSupported);
+        }
+        if self.raw_socket.is_some() {
+            // Without `can-isotp`, block size and STmin are meaningless -
+            // there's no kernel-side flow control to configure, and this
+            // fallback only ever sends/receives single frames anyway. Only
+            // the padding setting carries over.
+            self.raw_pad = cfg.pad_frame;
+            return Ok(());
+        }
+        Err(DiagError::NotSupported)
+    }
+}
@@ -0,0 +1,318 @@
+//! UDS services 0x34 (RequestDownload), 0x35 (RequestUpload), 0x36 (TransferData)
+//! and 0x37 (RequestTransferExit).
+//!
+//! Together these implement the ISO14229 block-transfer flow used to flash
+//! firmware onto an ECU, or to read a memory region back off of one (for
+//! example, extracting a crash dump).
+
+use auto_uds::UdsCommand;
+
+use crate::{
+    dynamic_diag::{with_pending_retry, PendingRetryOptions},
+    DiagError, DiagServerResult,
+};
+
+use super::UdsDiagnosticServer;
+
+/// Progress of an in-progress block transfer, reported after each block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// 1-based index of the block that was just transferred.
+    pub current_block: u32,
+    /// Total number of blocks the transfer will take.
+    pub total_blocks: u32,
+    /// Total bytes transferred so far.
+    pub bytes_transferred: usize,
+}
+
+/// Memory region addressed by RequestDownload/RequestUpload.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAddress {
+    /// Starting address of the region.
+    pub address: u32,
+    /// Size of the region, in bytes.
+    pub size: u32,
+}
+
+impl MemoryAddress {
+    /// Encodes `address` and `size` as the fewest big-endian bytes needed to
+    /// hold each value, as required by the addressAndLengthFormatIdentifier
+    /// field of RequestDownload/RequestUpload.
+    fn encode(self) -> (Vec<u8>, Vec<u8>) {
+        fn min_be_bytes(v: u32) -> Vec<u8> {
+            let b = v.to_be_bytes();
+            let first_nonzero = b.iter().position(|x| *x != 0).unwrap_or(3);
+            b[first_nonzero..].to_vec()
+        }
+        (min_be_bytes(self.address), min_be_bytes(self.size))
+    }
+}
+
+/// Number of bytes of overhead (SID + blockSequenceCounter) that must be
+/// subtracted from `maxNumberOfBlockLength` to get the usable payload size
+/// of each TransferData request.
+const TRANSFER_DATA_OVERHEAD: usize = 2;
+
+/// Computes the per-TransferData payload size and total block count for a
+/// region of `total_len` bytes, given the ECU-negotiated `max_block_len`
+/// (including the TransferData SID + counter overhead).
+fn transfer_plan(total_len: usize, max_block_len: usize) -> (usize, u32) {
+    let chunk_size = max_block_len.saturating_sub(TRANSFER_DATA_OVERHEAD).max(1);
+    let total_blocks = ((total_len + chunk_size - 1) / chunk_size).max(1) as u32;
+    (chunk_size, total_blocks)
+}
+
+fn request_transfer(
+    server: &mut UdsDiagnosticServer,
+    sid: UdsCommand,
+    data_format_identifier: u8,
+    mem: MemoryAddress,
+    retry: PendingRetryOptions,
+) -> DiagServerResult<usize> {
+    let (addr_bytes, size_bytes) = mem.encode();
+    let addr_size_len_fmt_id = ((size_bytes.len() as u8) << 4) | addr_bytes.len() as u8;
+
+    let mut args = vec![data_format_identifier, addr_size_len_fmt_id];
+    args.extend_from_slice(&addr_bytes);
+    args.extend_from_slice(&size_bytes);
+
+    let original_timeout = super::get_read_timeout(server);
+    let resp = with_pending_retry(retry, |is_retry| {
+        if is_retry {
+            let _ = super::set_read_timeout(server, retry.per_attempt_timeout_ms);
+        }
+        server.execute_command_with_response(sid, &args)
+    });
+    let _ = super::set_read_timeout(server, original_timeout);
+    let resp = resp?;
+    let len_fmt_id = *resp.first().ok_or(DiagError::InvalidResponseLength)?;
+    let num_len_bytes = (len_fmt_id >> 4) as usize;
+    if num_len_bytes == 0 || resp.len() < 1 + num_len_bytes {
+        return Err(DiagError::InvalidResponseLength);
+    }
+    let max_block_len = resp[1..1 + num_len_bytes]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok(max_block_len)
+}
+
+/// Sends RequestDownload (0x34), returning the ECU-negotiated
+/// `maxNumberOfBlockLength` (including the TransferData SID + counter
+/// overhead) for the upcoming TransferData requests.
+pub fn request_download(
+    server: &mut UdsDiagnosticServer,
+    data_format_identifier: u8,
+    mem: MemoryAddress,
+    retry: PendingRetryOptions,
+) -> DiagServerResult<usize> {
+    request_transfer(server, UdsCommand::RequestDownload, data_format_identifier, mem, retry)
+}
+
+/// Sends RequestUpload (0x35), returning the ECU-negotiated
+/// `maxNumberOfBlockLength` for the upcoming TransferData requests.
+pub fn request_upload(
+    server: &mut UdsDiagnosticServer,
+    data_format_identifier: u8,
+    mem: MemoryAddress,
+    retry: PendingRetryOptions,
+) -> DiagServerResult<usize> {
+    request_transfer(server, UdsCommand::RequestUpload, data_format_identifier, mem, retry)
+}
+
+/// Sends a single TransferData (0x36) request carrying `block_sequence_counter`
+/// and `payload`, returning the transferRequestParameterRecord the ECU echoed
+/// back. Errors if the ECU's echoed sequence counter doesn't match.
+///
+/// Re-sends the request with the same counter after NRC 0x78
+/// (ResponsePending) or NRC 0x21 (BusyRepeatRequest), up to the budgets in
+/// `retry`, so a block write/read taking longer than usual doesn't surface
+/// as an error.
+pub fn transfer_data(
+    server: &mut UdsDiagnosticServer,
+    block_sequence_counter: u8,
+    payload: &[u8],
+    retry: PendingRetryOptions,
+) -> DiagServerResult<Vec<u8>> {
+    let mut args = vec![block_sequence_counter];
+    args.extend_from_slice(payload);
+    let original_timeout = super::get_read_timeout(server);
+    let resp = with_pending_retry(retry, |is_retry| {
+        if is_retry {
+            let _ = super::set_read_timeout(server, retry.per_attempt_timeout_ms);
+        }
+        server.execute_command_with_response(UdsCommand::TransferData, &args)
+    });
+    let _ = super::set_read_timeout(server, original_timeout);
+    let resp = resp?;
+    if resp.first() != Some(&block_sequence_counter) {
+        return Err(DiagError::InvalidResponseLength);
+    }
+    Ok(resp[1..].to_vec())
+}
+
+/// Sends RequestTransferExit (0x37), ending the current download/upload.
+pub fn request_transfer_exit(
+    server: &mut UdsDiagnosticServer,
+    retry: PendingRetryOptions,
+) -> DiagServerResult<Vec<u8>> {
+    let original_timeout = super::get_read_timeout(server);
+    let result = with_pending_retry(retry, |is_retry| {
+        if is_retry {
+            let _ = super::set_read_timeout(server, retry.per_attempt_timeout_ms);
+        }
+        server.execute_command_with_response(UdsCommand::RequestTransferExit, &[])
+    });
+    let _ = super::set_read_timeout(server, original_timeout);
+    result
+}
+
+/// Outcome of a block-wise [`upload`].
+#[derive(Debug, Clone)]
+pub enum UploadOutcome {
+    /// The full region was read back successfully.
+    Complete(Vec<u8>),
+    /// The `progress` callback requested an abort. Holds whatever was read
+    /// before the abort, so callers can resume or discard it as needed.
+    Aborted {
+        /// Bytes read before the abort.
+        bytes_read: Vec<u8>,
+        /// Number of blocks read before the abort.
+        blocks_read: u32,
+    },
+}
+
+/// Reads `mem.size` bytes starting at `mem.address` off the ECU, using
+/// RequestUpload followed by successive TransferData blocks.
+///
+/// `progress` is invoked after each block is read, and should return `false`
+/// to abort the upload (for example a crash-dump read cancelled by the
+/// user); the bytes read so far are returned rather than discarded.
+pub fn upload(
+    server: &mut UdsDiagnosticServer,
+    data_format_identifier: u8,
+    mem: MemoryAddress,
+    retry: PendingRetryOptions,
+    mut progress: impl FnMut(TransferProgress) -> bool,
+) -> DiagServerResult<UploadOutcome> {
+    let max_block_len = request_upload(server, data_format_identifier, mem, retry)?;
+    let (_chunk_size, total_blocks) = transfer_plan(mem.size as usize, max_block_len);
+
+    let mut counter: u8 = 0x01;
+    let mut out = Vec::with_capacity(mem.size as usize);
+    let mut blocks_read = 0u32;
+    while out.len() < mem.size as usize {
+        let chunk = transfer_data(server, counter, &[], retry)?;
+        out.extend_from_slice(&chunk);
+        blocks_read += 1;
+        counter = counter.wrapping_add(1);
+
+        let keep_going = progress(TransferProgress {
+            current_block: blocks_read,
+            total_blocks,
+            bytes_transferred: out.len(),
+        });
+        if !keep_going {
+            return Ok(UploadOutcome::Aborted {
+                bytes_read: out,
+                blocks_read,
+            });
+        }
+    }
+    // The final block may be padded past `mem.size` (the ECU is free to
+    // round up to its block length), so trim back to exactly the region
+    // that was asked for.
+    out.truncate(mem.size as usize);
+    request_transfer_exit(server, retry)?;
+    Ok(UploadOutcome::Complete(out))
+}
+
+/// Flashes `data` onto the ECU at `mem.address` using RequestDownload,
+/// successive TransferData blocks and RequestTransferExit.
+///
+/// `progress` is invoked after each block is transferred.
+pub fn download(
+    server: &mut UdsDiagnosticServer,
+    data_format_identifier: u8,
+    mem: MemoryAddress,
+    data: &[u8],
+    retry: PendingRetryOptions,
+    mut progress: impl FnMut(TransferProgress),
+) -> DiagServerResult<()> {
+    if data.len() != mem.size as usize {
+        // `mem.size` is what RequestDownload tells the ECU to expect; if it
+        // doesn't match `data`, the ECU ends up expecting a different
+        // number of bytes than the TransferData loop below actually sends.
+        return Err(DiagError::InvalidResponseLength);
+    }
+    let max_block_len = request_download(server, data_format_identifier, mem, retry)?;
+    let (chunk_size, total_blocks) = transfer_plan(data.len(), max_block_len);
+
+    let mut counter: u8 = 0x01;
+    let mut bytes_transferred = 0usize;
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        transfer_data(server, counter, chunk, retry)?;
+        bytes_transferred += chunk.len();
+        progress(TransferProgress {
+            current_block: i as u32 + 1,
+            total_blocks,
+            bytes_transferred,
+        });
+        counter = counter.wrapping_add(1);
+    }
+    request_transfer_exit(server, retry)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_plan_divides_evenly() {
+        assert_eq!(transfer_plan(100, 12), (10, 10));
+    }
+
+    #[test]
+    fn transfer_plan_rounds_up_a_partial_final_block() {
+        assert_eq!(transfer_plan(105, 12), (10, 11));
+    }
+
+    #[test]
+    fn transfer_plan_floors_chunk_size_at_one_byte() {
+        // `max_block_len` at or below `TRANSFER_DATA_OVERHEAD` must not
+        // divide by zero - the chunk size floors at 1 byte per block.
+        assert_eq!(transfer_plan(3, 1), (1, 3));
+    }
+
+    #[test]
+    fn transfer_plan_always_reports_at_least_one_block() {
+        assert_eq!(transfer_plan(0, 12), (10, 1));
+    }
+
+    #[test]
+    fn memory_address_encodes_minimal_be_bytes() {
+        let (addr, size) = MemoryAddress { address: 0x1234, size: 0x10 }.encode();
+        assert_eq!(addr, vec![0x12, 0x34]);
+        assert_eq!(size, vec![0x10]);
+    }
+
+    #[test]
+    fn memory_address_encodes_zero_as_a_single_byte() {
+        let (addr, size) = MemoryAddress { address: 0, size: 0 }.encode();
+        assert_eq!(addr, vec![0x00]);
+        assert_eq!(size, vec![0x00]);
+    }
+
+    #[test]
+    fn block_sequence_counter_wraps_from_0xff_to_0x00() {
+        // `upload`/`download` both start at 0x01 and advance with
+        // `wrapping_add(1)` for every block - for a transfer long enough to
+        // need more than 255 blocks, the counter must roll over to 0x00
+        // rather than panicking or getting stuck at 0xFF.
+        let mut counter: u8 = 0x01;
+        for _ in 0..0xFFu32 {
+            counter = counter.wrapping_add(1);
+        }
+        assert_eq!(counter, 0x00);
+    }
+}
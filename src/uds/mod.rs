@@ -9,6 +9,7 @@ use crate::{dynamic_diag::{DiagProtocol, EcuNRC, DiagSessionMode, DiagAction, Di
 mod access_timing_parameter;
 mod clear_diagnostic_information;
 mod communication_control;
+mod data_transfer;
 mod diagnostic_session_control;
 mod ecu_reset;
 mod read_dtc_information;
@@ -19,6 +20,7 @@ pub use access_timing_parameter::*;
 use auto_uds::{UdsCommand, ByteWrapper, UdsErrorByte};
 pub use clear_diagnostic_information::*;
 pub use communication_control::*;
+pub use data_transfer::*;
 pub use diagnostic_session_control::*;
 pub use ecu_reset::*;
 pub use read_dtc_information::*;